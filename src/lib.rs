@@ -24,13 +24,35 @@ pub use rusty_fork::{fork, rusty_fork_id, rusty_fork_test_name, ChildWrapper};
 
 use anyhow::Context;
 use fs_err::create_dir_all;
+use globset::{Glob, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
+  cell::RefCell,
+  collections::BTreeMap,
   env,
   error::Error,
   fs::copy,
-  path::{Path, PathBuf},
+  path::{Component, Path, PathBuf},
 };
-use tempfile::{Builder, TempDir};
+use tempfile::{Builder, NamedTempFile, TempDir};
+use walkdir::WalkDir;
+
+/// Above this size a file is snapshotted as a content hash rather than inlined verbatim, to
+/// keep golden files reviewable in a PR diff.
+const SNAPSHOT_INLINE_LIMIT_BYTES: u64 = 4 * 1024;
+
+/// A normalized, comparable record of one test's filesystem output: relative path to either
+/// its content hash or, for small text files, the content itself. Kept as a `BTreeMap` so
+/// `assert_eq_sorted!` can produce an added/removed/changed diff against the golden file.
+type Manifest = BTreeMap<String, FileSnapshot>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum FileSnapshot {
+  Hash(String),
+  Inline(String),
+}
 
 enum TestWorkingDirectory {
   Temporary(TempDir),
@@ -50,6 +72,9 @@ impl TestWorkingDirectory {
 pub struct PrivateFS {
   ran_from: PathBuf,
   directory: TestWorkingDirectory,
+  // Absolute paths written by `#[include(...)]`/`#[include(link = ...)]`, so `snapshot` can
+  // tell fixture inputs apart from whatever the test body itself wrote.
+  included: RefCell<Vec<PathBuf>>,
 }
 
 impl PrivateFS {
@@ -60,6 +85,43 @@ impl PrivateFS {
     Ok(Self {
       ran_from,
       directory: TestWorkingDirectory::Temporary(directory),
+      included: RefCell::new(vec![]),
+    })
+  }
+
+  /// Like [`PrivateFS::temporary`], but on Linux it additionally tries to `unshare` a
+  /// private mount namespace and bind-mount the tempdir onto a deterministic path
+  /// ([`namespace_isolation::MOUNTPOINT`]) so every test sees the same absolute CWD and can't
+  /// reach the host's real `/tmp` or `$HOME` through it. Used by the fork entrypoint in place
+  /// of `temporary` when the test is marked for namespace isolation.
+  ///
+  /// If the namespace can't be created (unprivileged container, missing `CONFIG_USER_NS`,
+  /// non-Linux host, ...) this logs why and falls back to a plain tempdir, exactly like
+  /// `temporary`.
+  #[cfg(feature = "namespace-isolation")]
+  pub fn namespaced() -> Result<Self, Box<dyn Error>> {
+    let ran_from = env::current_dir()?;
+    let directory = Builder::new().prefix("private").tempdir()?;
+
+    match namespace_isolation::isolate(directory.path()) {
+      Ok(()) => {
+        env::set_current_dir(namespace_isolation::MOUNTPOINT).context(
+          "Failed to change into the bind-mounted, namespace-isolated test working directory",
+        )?;
+      }
+      Err(e) => {
+        eprintln!(
+          "assay: namespace isolation unavailable ({}), falling back to a plain tempdir",
+          e
+        );
+        env::set_current_dir(directory.path())?;
+      }
+    }
+
+    Ok(Self {
+      ran_from,
+      directory: TestWorkingDirectory::Temporary(directory),
+      included: RefCell::new(vec![]),
     })
   }
 
@@ -72,6 +134,7 @@ impl PrivateFS {
     Ok(Self {
       ran_from,
       directory: TestWorkingDirectory::Rooted(root.to_path_buf()),
+      included: RefCell::new(vec![]),
     })
   }
 
@@ -84,6 +147,15 @@ impl PrivateFS {
     S: AsRef<Path>,
     D: AsRef<Path>,
   {
+    let destination_path = destination_path.map(|p| p.as_ref().to_owned());
+
+    // Glob patterns are handled by a dedicated path: they can expand to any
+    // number of files/directories, so they can't be dispatched to
+    // `include_file`/`include_directory` directly.
+    if is_glob_pattern(source_path.as_ref()) {
+      return self.include_glob(source_path.as_ref(), &destination_path, false);
+    }
+
     // Get our pathbuf to the file/directory to include
     let inner_path = {
       let mut p = source_path.as_ref().to_owned();
@@ -96,8 +168,6 @@ impl PrivateFS {
       p
     };
 
-    let destination_path = destination_path.map(|p| p.as_ref().to_owned());
-
     if inner_path.is_file() {
       self.include_file(inner_path, &destination_path)?;
     } else if inner_path.is_dir() {
@@ -111,6 +181,118 @@ impl PrivateFS {
     Ok(())
   }
 
+  /// Expand a glob pattern (e.g. `"fixtures/**/*.json"`) rooted at the directory the test
+  /// was run from, and copy every match into the test working directory.
+  ///
+  /// The destination for each match is its path relative to the longest non-glob prefix of
+  /// the pattern, joined onto `destination_path` when one is given. `.gitignore`/`.ignore`
+  /// files in the ancestors of that prefix and in every directory below it are honored
+  /// unless `ignore_overrides` is set.
+  fn include_glob(
+    &self,
+    pattern: &Path,
+    destination_path: &Option<PathBuf>,
+    ignore_overrides: bool,
+  ) -> Result<(), Box<dyn Error>> {
+    let pattern = if pattern.is_relative() {
+      self.ran_from.join(pattern)
+    } else {
+      pattern.to_owned()
+    };
+
+    let base = glob_base_dir(&pattern);
+
+    let mut builder = GlobSetBuilder::new();
+    builder.add(
+      Glob::new(&pattern.to_string_lossy())
+        .context("Failed to parse the glob pattern passed to `#[include()]`")?,
+    );
+    let glob_set = builder
+      .build()
+      .context("Failed to compile the glob pattern passed to `#[include()]`")?;
+
+    // Per-directory stack of `.gitignore`/`.ignore` matchers: the matcher at the top of the
+    // stack is the one built from the directory we're currently descending through, so a
+    // deeper ignore file naturally overrides a shallower one when we check a path against
+    // the whole stack from the top down.
+    let mut ignore_stack: Vec<(PathBuf, Gitignore)> = vec![];
+    if !ignore_overrides {
+      for ancestor in base.ancestors().collect::<Vec<_>>().into_iter().rev() {
+        if let Some(ignore) = ignore_for_directory(ancestor) {
+          ignore_stack.push((ancestor.to_owned(), ignore));
+        }
+      }
+    }
+
+    // A plain `for` loop only gets an owned iterator, which can't be told to stop
+    // descending into a directory — we need `it.skip_current_dir()` below so an ignored
+    // directory's *contents* aren't walked at all, rather than visited and rejected one
+    // entry at a time.
+    let mut it = WalkDir::new(&base).into_iter();
+    loop {
+      // An unreadable subdirectory, a symlink loop, or a racing deletion shouldn't abort
+      // the whole `#[include()]` — skip the bad entry and keep walking, same as the
+      // `.filter_map(|e| e.ok())` walks elsewhere in this file.
+      let entry = match it.next() {
+        Some(Ok(entry)) => entry,
+        Some(Err(_)) => continue,
+        None => break,
+      };
+      let path = entry.path();
+      let is_dir = entry.file_type().is_dir();
+
+      if !ignore_overrides {
+        while ignore_stack
+          .last()
+          .map(|(dir, _)| !path.starts_with(dir))
+          .unwrap_or(false)
+        {
+          ignore_stack.pop();
+        }
+        // `matched_path_or_any_parents` (rather than `matched`) so a rule like `target/`
+        // recorded against an ancestor still applies to paths nested arbitrarily deep
+        // beneath it, not just to the directory entry it literally names.
+        let is_ignored = ignore_stack.iter().rev().find_map(|(_, ignore)| {
+          match ignore.matched_path_or_any_parents(path, is_dir) {
+            m if m.is_ignore() => Some(true),
+            m if m.is_whitelist() => Some(false),
+            _ => None,
+          }
+        });
+        if is_ignored == Some(true) {
+          if is_dir {
+            it.skip_current_dir();
+          }
+          continue;
+        }
+        if is_dir {
+          if let Some(ignore) = ignore_for_directory(path) {
+            ignore_stack.push((path.to_owned(), ignore));
+          }
+        }
+      }
+
+      if !entry.file_type().is_file() {
+        continue;
+      }
+      if !glob_set.is_match(path) {
+        continue;
+      }
+
+      let relative = path
+        .strip_prefix(&base)
+        .expect("walkdir always yields paths nested under the base directory")
+        .to_owned();
+      let destination = match destination_path {
+        Some(root) => root.join(&relative),
+        None => relative,
+      };
+      self.include_file(path.to_owned(), &Some(destination))?;
+    }
+
+    Ok(())
+  }
+
   fn include_file(
     &self,
     inner_path: PathBuf,
@@ -150,8 +332,9 @@ impl PrivateFS {
     };
 
     // Copy the file over from the file system into the temp file system
-    copy(inner_path, destination_path)
+    copy(inner_path, &destination_path)
       .context("Failed to copy a file into the test working directory")?;
+    self.record_included(destination_path);
     Ok(())
   }
 
@@ -186,10 +369,737 @@ impl PrivateFS {
     let mut o = fs_extra::dir::CopyOptions::new();
     o.content_only = true;
     // Copy the file over from the file system into the temp file system
-    fs_extra::dir::copy(inner_path, destination_path, &o)
+    fs_extra::dir::copy(&inner_path, &destination_path, &o)
       .context("Failed to copy the content of a directory into the test working directory")?;
+
+    // Record the individual files this copy produced, rather than `destination_path` as a
+    // whole: when `destination_path` is the test's root directory (the "no explicit
+    // destination" case above), treating the *whole* root as "included" would exclude
+    // everything the test itself writes there too.
+    for entry in WalkDir::new(&inner_path)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+    {
+      let relative = entry
+        .path()
+        .strip_prefix(&inner_path)
+        .expect("walkdir always yields paths nested under the directory it was given");
+      self.record_included(destination_path.join(relative));
+    }
     Ok(())
   }
+
+  /// Record an absolute path written by `#[include(...)]`/`#[include(link = ...)]` so
+  /// `build_manifest` can exclude it from a `#[snapshot(...)]` assertion: the snapshot is
+  /// meant to capture what the test *wrote*, not the fixtures it was seeded with.
+  fn record_included(&self, absolute_path: PathBuf) {
+    self.included.borrow_mut().push(absolute_path);
+  }
+
+  /// Mount a fixture by linking rather than copying it, for large read-only fixtures that
+  /// are wasteful to byte-copy into every test's tempdir. Files are hard-linked; directories
+  /// are symlinked as a whole, since there's no way to "merge" a symlink's contents into an
+  /// existing directory the way `include_directory` merges a copy.
+  pub fn include_link<S, D>(
+    &self,
+    source_path: S,
+    destination_path: Option<D>,
+  ) -> Result<(), Box<dyn Error>>
+  where
+    S: AsRef<Path>,
+    D: AsRef<Path>,
+  {
+    // Resolve to an absolute path anchored at the dir we ran the test from *before*
+    // `set_current_dir` moved us into the tempdir, otherwise a relative link target would
+    // end up pointing at the wrong place once the CWD changes.
+    let inner_path = {
+      let mut p = source_path.as_ref().to_owned();
+      if p.is_relative() {
+        p = self.ran_from.join(&source_path);
+      }
+      p
+    };
+
+    let destination_path = destination_path.map(|p| p.as_ref().to_owned());
+
+    if inner_path.is_file() {
+      self.link_file(inner_path, &destination_path)
+    } else if inner_path.is_dir() {
+      self.link_directory(inner_path, &destination_path)
+    } else {
+      panic!(
+        "The source path passed to `#[include(link = ...)]` must point to a file or a directory. {:?} is neither.",
+        inner_path
+      );
+    }
+  }
+
+  fn link_file(
+    &self,
+    inner_path: PathBuf,
+    destination_path: &Option<PathBuf>,
+  ) -> Result<(), Box<dyn Error>> {
+    // Get our working directory
+    let dir = self.directory.path().to_owned();
+
+    let destination_path = match destination_path {
+      None => {
+        // If the destination path is unspecified, we link the file in the root directory
+        // of the test's private filesystem
+        match inner_path.file_name() {
+          Some(filename) => dir.join(filename),
+          None => {
+            panic!(
+              "Failed to extract the filename from the source path, {:?}.",
+              inner_path
+            )
+          }
+        }
+      }
+      Some(p) => {
+        if !p.is_relative() {
+          panic!(
+            "The destination path for linked files must be a relative path. {:?} isn't.",
+            p
+          );
+        }
+        if let Some(parent) = p.parent() {
+          create_dir_all(dir.join(parent)).context("Failed to create the parent directory for a file that should have been linked into the test working directory")?;
+        }
+        dir.join(p)
+      }
+    };
+
+    // Hard-link rather than copy: the fixture is shared, read-only, and potentially huge.
+    #[cfg(unix)]
+    std::fs::hard_link(&inner_path, &destination_path)
+      .context("Failed to hard-link a file into the test working directory")?;
+    #[cfg(windows)]
+    std::fs::hard_link(&inner_path, &destination_path)
+      .or_else(|_| std::os::windows::fs::symlink_file(&inner_path, &destination_path))
+      .context("Failed to link a file into the test working directory")?;
+
+    self.record_included(destination_path);
+    Ok(())
+  }
+
+  fn link_directory(
+    &self,
+    inner_path: PathBuf,
+    destination_path: &Option<PathBuf>,
+  ) -> Result<(), Box<dyn Error>> {
+    // Get our working directory
+    let dir = self.directory.path().to_owned();
+
+    let destination_path = match destination_path {
+      None => match inner_path.file_name() {
+        Some(filename) => dir.join(filename),
+        None => {
+          panic!(
+            "Failed to extract the directory name from the source path, {:?}.",
+            inner_path
+          )
+        }
+      },
+      Some(p) => {
+        if !p.is_relative() {
+          panic!(
+            "The destination path for a linked directory must be a relative path. {:?} isn't.",
+            p
+          );
+        }
+        if let Some(parent) = p.parent() {
+          create_dir_all(dir.join(parent)).context("Failed to create the parent directory for a directory that will be linked into the test working directory")?;
+        }
+        dir.join(p)
+      }
+    };
+
+    // Symlink the whole directory rather than walking and hard-linking every entry: the
+    // fixture is assumed to be read-only, so there's no need to reproduce its structure,
+    // only to make it reachable at `destination_path`.
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(&inner_path, &destination_path)
+      .context("Failed to symlink a directory into the test working directory")?;
+    #[cfg(windows)]
+    std::os::windows::fs::symlink_dir(&inner_path, &destination_path)
+      .context("Failed to symlink a directory into the test working directory")?;
+
+    // The whole subtree reachable through this symlink is fixture input, so a single
+    // recorded path is enough for `build_manifest` to exclude all of it via a prefix match.
+    self.record_included(destination_path);
+    Ok(())
+  }
+
+  /// Assert that the test working directory's contents match the golden snapshot at
+  /// `snapshot_path`. Meant to be called after the test body runs, turning the private FS
+  /// from an input-only sandbox into an input/output fixture.
+  ///
+  /// Files mounted by `#[include(...)]`/`#[include(link = ...)]` are excluded from the
+  /// manifest by path, so the snapshot reflects what the test *wrote*, not the fixtures it
+  /// was seeded with. The one case this can't distinguish: the test overwriting one of those
+  /// same paths in place — that file is still treated as fixture input and left out, since
+  /// `assay` only tracks *which* paths were populated by `#[include(...)]`, not whether the
+  /// test subsequently modified them.
+  ///
+  /// Set `ASSAY_UPDATE_SNAPSHOTS` (to any value, e.g. `1`) to (re)write the golden file from
+  /// the current working directory's contents instead of comparing against it. There is no
+  /// `--bless` flag; the environment variable is the only switch. The same thing happens automatically the first time, when the
+  /// golden file doesn't exist yet.
+  pub fn snapshot(&self, snapshot_path: impl AsRef<Path>) -> Result<(), Box<dyn Error>> {
+    // By the time `snapshot` runs, `set_current_dir` has already moved the process into the
+    // tempdir, so a relative path here — like a relative source in `include` — must be
+    // anchored at the directory the test was run from, not at the ephemeral tempdir.
+    let snapshot_path = snapshot_path.as_ref();
+    let snapshot_path = if snapshot_path.is_relative() {
+      self.ran_from.join(snapshot_path)
+    } else {
+      snapshot_path.to_owned()
+    };
+    let snapshot_path = snapshot_path.as_path();
+
+    let actual = self.build_manifest()?;
+
+    let blessing = env::var_os("ASSAY_UPDATE_SNAPSHOTS").is_some();
+    if blessing || !snapshot_path.is_file() {
+      write_snapshot_atomically(snapshot_path, &actual)?;
+      return Ok(());
+    }
+
+    let raw = fs_err::read_to_string(snapshot_path)
+      .context("Failed to read the golden snapshot file")?;
+    let expected: Manifest = serde_json::from_str(&raw)
+      .context("Failed to parse the golden snapshot file as a filesystem manifest")?;
+
+    crate::assert_eq_sorted!(
+      actual,
+      expected,
+      "the test working directory doesn't match the golden snapshot at {:?} (rerun with ASSAY_UPDATE_SNAPSHOTS=1 to update it)",
+      snapshot_path
+    );
+
+    Ok(())
+  }
+
+  /// Walk the test working directory and build a normalized manifest of its contents:
+  /// relative path to either a content hash, or, for small text files, the content itself
+  /// inlined so a reviewer can see what changed without leaving the diff.
+  fn build_manifest(&self) -> Result<Manifest, Box<dyn Error>> {
+    let root = self.directory.path();
+    let mut manifest = Manifest::new();
+    let included = self.included.borrow();
+
+    for entry in WalkDir::new(root)
+      .into_iter()
+      .filter_map(|e| e.ok())
+      .filter(|e| e.file_type().is_file())
+    {
+      let path = entry.path();
+
+      // Skip anything that came in via `#[include(...)]`/`#[include(link = ...)]`: a
+      // snapshot is meant to capture what the test *wrote*, not the fixtures it started
+      // from, so a fixture input changing shouldn't make an unrelated golden file stale.
+      if included.iter().any(|included_path| path.starts_with(included_path)) {
+        continue;
+      }
+
+      let relative = path
+        .strip_prefix(root)
+        .expect("walkdir always yields paths nested under the root it was given")
+        .to_string_lossy()
+        .replace('\\', "/"); // Normalize Windows separators so snapshots are portable.
+
+      let metadata = entry
+        .metadata()
+        .context("Failed to read metadata for a file under the test working directory")?;
+      let contents = fs_err::read(path)
+        .context("Failed to read a file under the test working directory for snapshotting")?;
+
+      let snapshot = if metadata.len() <= SNAPSHOT_INLINE_LIMIT_BYTES {
+        match String::from_utf8(contents) {
+          Ok(text) => FileSnapshot::Inline(text),
+          Err(e) => FileSnapshot::Hash(hash_bytes(e.as_bytes())),
+        }
+      } else {
+        FileSnapshot::Hash(hash_bytes(&contents))
+      };
+
+      manifest.insert(relative, snapshot);
+    }
+
+    Ok(manifest)
+  }
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(bytes);
+  format!("sha256:{:x}", hasher.finalize())
+}
+
+/// Write `manifest` to `snapshot_path` as pretty-printed JSON, going through a temp file in
+/// the same directory plus a rename so a crash mid-write can't leave a half-written golden
+/// file behind.
+fn write_snapshot_atomically(
+  snapshot_path: &Path,
+  manifest: &Manifest,
+) -> Result<(), Box<dyn Error>> {
+  let parent = snapshot_path
+    .parent()
+    .filter(|p| !p.as_os_str().is_empty())
+    .unwrap_or_else(|| Path::new("."));
+  create_dir_all(parent).context("Failed to create the parent directory of the snapshot file")?;
+
+  let mut tmp = NamedTempFile::new_in(parent)
+    .context("Failed to create a temp file to stage the snapshot write")?;
+  serde_json::to_writer_pretty(&mut tmp, manifest)
+    .context("Failed to serialize the filesystem manifest")?;
+  tmp
+    .persist(snapshot_path)
+    .context("Failed to atomically rename the staged snapshot into place")?;
+
+  Ok(())
+}
+
+/// Does `path` contain any glob metacharacters? Used to decide whether `#[include()]` should
+/// treat its source path as a literal file/directory or expand it as a pattern.
+fn is_glob_pattern(path: &Path) -> bool {
+  path
+    .to_string_lossy()
+    .chars()
+    .any(|c| matches!(c, '*' | '?' | '[' | ']' | '{' | '}'))
+}
+
+/// The longest prefix of `pattern` that contains no glob metacharacters, i.e. the directory
+/// we need to walk in order to find every match.
+fn glob_base_dir(pattern: &Path) -> PathBuf {
+  let mut base = PathBuf::new();
+  for component in pattern.components() {
+    let is_glob_component = matches!(component, Component::Normal(c) if is_glob_pattern(Path::new(c)));
+    if is_glob_component {
+      break;
+    }
+    base.push(component);
+  }
+  base
+}
+
+/// Build a `Gitignore` matcher from the `.gitignore`/`.ignore` files directly inside `dir`,
+/// if any exist. Returns `None` when neither file is present, so callers don't push a
+/// no-op matcher onto the ignore stack.
+fn ignore_for_directory(dir: &Path) -> Option<Gitignore> {
+  let mut builder = GitignoreBuilder::new(dir);
+  let mut found_one = false;
+  for name in [".gitignore", ".ignore"] {
+    let candidate = dir.join(name);
+    if candidate.is_file() {
+      found_one = true;
+      // A malformed ignore file shouldn't abort the whole `#[include()]` walk; it just
+      // means that file's rules are skipped.
+      let _ = builder.add(candidate);
+    }
+  }
+  if !found_one {
+    return None;
+  }
+  builder.build().ok()
+}
+
+// Mount-namespace isolation: an opt-in, stronger alternative to plain `set_current_dir` into
+// a tempdir. Lives behind the `namespace-isolation` feature because it pulls in `nix` and
+// only does anything useful on Linux.
+#[cfg(feature = "namespace-isolation")]
+mod namespace_isolation {
+  use std::path::Path;
+
+  /// The path every namespace-isolated test is bind-mounted at, regardless of where the
+  /// backing tempdir actually lives on the host. Deterministic across runs and machines.
+  pub const MOUNTPOINT: &str = "/assay";
+
+  #[cfg(target_os = "linux")]
+  pub fn isolate(tempdir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    use nix::mount::{mount, MsFlags};
+    use nix::sched::{unshare, CloneFlags};
+
+    // Give this process its own view of the mount table so the mounts below aren't visible
+    // to (and can't be tampered with by) anything outside the forked test process.
+    unshare(CloneFlags::CLONE_NEWNS)?;
+
+    // Without this, the mount/bind-mount below would propagate back to the parent's mount
+    // namespace (and the host), defeating the whole point.
+    mount(
+      None::<&str>,
+      "/",
+      None::<&str>,
+      MsFlags::MS_PRIVATE | MsFlags::MS_REC,
+      None::<&str>,
+    )?;
+
+    std::fs::create_dir_all(MOUNTPOINT)?;
+
+    // A fresh tmpfs at the mountpoint, so nothing pre-existing at that path (on a host that
+    // happens to have one) leaks into the test.
+    mount(
+      Some("tmpfs"),
+      MOUNTPOINT,
+      Some("tmpfs"),
+      MsFlags::empty(),
+      None::<&str>,
+    )?;
+
+    // Bind-mount the actual, per-test private tempdir over that same deterministic path.
+    mount(
+      Some(tempdir),
+      MOUNTPOINT,
+      None::<&str>,
+      MsFlags::MS_BIND,
+      None::<&str>,
+    )?;
+
+    Ok(())
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  pub fn isolate(_tempdir: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    Err("mount-namespace isolation is only implemented on Linux".into())
+  }
+}
+
+// Pluggable execution backends for the forked test process. `assay` has always run each
+// test's body in its own process via `rusty_fork`; this makes *where* that process runs
+// swappable, so `#[assay(runner = "ssh://user@host")]` can dispatch a test to a remote host
+// instead of forking locally, without the macro-generated harness calling `rusty_fork::fork`
+// directly.
+#[doc(hidden)]
+pub mod runner {
+  use super::*;
+  use std::process::{Command, Stdio};
+
+  /// Everything a [`TestRunner`] needs in order to execute one `#[assay]` test body: which
+  /// binary/filter identifies it, the `PrivateFS` contents gathered by `#[include(...)]` to
+  /// replicate, and the env vars the test was configured with.
+  pub struct TestSpec<'a> {
+    pub test_binary: &'a Path,
+    pub test_filter: &'a str,
+    pub fs_root: Option<&'a Path>,
+    pub env: &'a [(String, String)],
+  }
+
+  /// What came back from running a test, regardless of which backend ran it.
+  ///
+  /// A non-zero exit is reported as `success: false`, not as an `Err` from
+  /// [`TestRunner::run`] — `Err` is reserved for the backend itself failing (lost
+  /// connection, file-transfer error, ...), which the harness should treat differently from
+  /// an ordinary test failure.
+  pub struct TestOutcome {
+    pub success: bool,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+  }
+
+  /// A transport for running one `#[assay]` test body. The macro-generated harness calls
+  /// through this trait instead of forking locally, so the test can be dispatched elsewhere.
+  pub trait TestRunner {
+    fn run(&self, spec: &TestSpec<'_>) -> Result<TestOutcome, Box<dyn Error>>;
+  }
+
+  /// The default backend, and the only one available without the `ssh-runner` feature: run
+  /// the test binary in a child process on this machine.
+  pub struct LocalFork;
+
+  impl TestRunner for LocalFork {
+    fn run(&self, spec: &TestSpec<'_>) -> Result<TestOutcome, Box<dyn Error>> {
+      let output = Command::new(spec.test_binary)
+        .arg("--exact")
+        .arg(spec.test_filter)
+        .envs(spec.env.iter().cloned())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .context("Failed to fork the local test process")?;
+
+      Ok(TestOutcome {
+        success: output.status.success(),
+        stdout: output.stdout,
+        stderr: output.stderr,
+      })
+    }
+  }
+
+  /// Picks the backend named by a `#[assay(runner = "...")]` attribute. `None` (the
+  /// attribute omitted) selects [`LocalFork`], matching `assay`'s existing behavior.
+  pub fn resolve(target: Option<&str>) -> Result<Box<dyn TestRunner>, Box<dyn Error>> {
+    match target {
+      None => Ok(Box::new(LocalFork)),
+      #[cfg(feature = "ssh-runner")]
+      Some(target) if target.starts_with("ssh://") => Ok(Box::new(SshRunner::parse(target)?)),
+      #[cfg(not(feature = "ssh-runner"))]
+      Some(target) if target.starts_with("ssh://") => Err(format!(
+        "Runner target {:?} requires the `ssh-runner` feature to be enabled",
+        target
+      )
+      .into()),
+      Some(other) => Err(format!("Unrecognized `#[assay(runner = ...)]` target: {:?}", other).into()),
+    }
+  }
+
+  /// Dispatches a test to a remote host over SSH: replicates the gathered `PrivateFS`
+  /// contents to a remote temp directory, exports the test's env vars, invokes the test
+  /// binary remotely with the same `--exact <filter>` the local backend uses, and streams
+  /// stdout/stderr/the exit status back before cleaning up the remote temp dir.
+  #[cfg(feature = "ssh-runner")]
+  pub struct SshRunner {
+    user: Option<String>,
+    host: String,
+    port: u16,
+    remote_tmp_prefix: String,
+  }
+
+  #[cfg(feature = "ssh-runner")]
+  impl SshRunner {
+    /// Parse a `ssh://[user@]host[:port]` runner target, as written in
+    /// `#[assay(runner = "...")]`. Defaults to port 22 when none is given.
+    pub fn parse(target: &str) -> Result<Self, Box<dyn Error>> {
+      let rest = target
+        .strip_prefix("ssh://")
+        .ok_or_else(|| anyhow::anyhow!("Runner target {:?} isn't an `ssh://` URL", target))?;
+      let (user, host_port) = match rest.split_once('@') {
+        Some((user, host_port)) => (Some(user.to_owned()), host_port),
+        None => (None, rest),
+      };
+      let (host, port) = match host_port.rsplit_once(':') {
+        Some((host, port)) => (
+          host.to_owned(),
+          port
+            .parse()
+            .with_context(|| format!("Invalid port in runner target {:?}", target))?,
+        ),
+        None => (host_port.to_owned(), 22),
+      };
+      Ok(Self {
+        user,
+        host,
+        port,
+        remote_tmp_prefix: "/tmp/assay".to_owned(),
+      })
+    }
+
+    fn connect(&self) -> Result<ssh2::Session, Box<dyn Error>> {
+      use std::net::TcpStream;
+
+      let tcp = TcpStream::connect((self.host.as_str(), self.port)).with_context(|| {
+        format!(
+          "Failed to open a TCP connection to {:?} on port {}",
+          self.host, self.port
+        )
+      })?;
+      let mut session = ssh2::Session::new().context("Failed to create an SSH session")?;
+      session.set_tcp_stream(tcp);
+      session
+        .handshake()
+        .with_context(|| format!("SSH handshake with {:?} failed", self.host))?;
+      session
+        .userauth_agent(self.user.as_deref().unwrap_or("root"))
+        .with_context(|| format!("SSH authentication to {:?} failed", self.host))?;
+      Ok(session)
+    }
+
+    fn replicate_fs(
+      &self,
+      session: &ssh2::Session,
+      remote_dir: &str,
+      spec: &TestSpec<'_>,
+    ) -> Result<(), Box<dyn Error>> {
+      use std::io::Write;
+
+      let sftp = session.sftp().context("Failed to open an SFTP channel")?;
+      // `remote_dir` (e.g. `/tmp/assay/<filter>`) may be several levels below anything that
+      // already exists on a fresh host, and `Sftp::mkdir` isn't recursive — create every
+      // ancestor in order, ignoring "already exists" so retries aren't fatal.
+      mkdir_recursive(&sftp, remote_dir);
+
+      // The remote host has no copy of the test binary until we put one there, so upload it
+      // alongside the fixture contents and mark it executable — `sftp.create` otherwise
+      // leaves it with the SFTP server's default (non-executable) permissions.
+      let binary_contents = fs_err::read(spec.test_binary)
+        .context("Failed to read the test binary to upload over SSH")?;
+      let remote_binary_path = format!("{}/{}", remote_dir, remote_binary_name(spec.test_binary));
+      {
+        let mut remote_binary = sftp
+          .create(Path::new(&remote_binary_path))
+          .context("Failed to create the remote test binary over SFTP")?;
+        remote_binary
+          .write_all(&binary_contents)
+          .context("Failed to upload the test binary over SFTP")?;
+      }
+      sftp
+        .setstat(
+          Path::new(&remote_binary_path),
+          ssh2::FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(0o755),
+            atime: None,
+            mtime: None,
+          },
+        )
+        .context("Failed to mark the uploaded test binary executable")?;
+
+      let Some(fs_root) = spec.fs_root else {
+        return Ok(());
+      };
+      for entry in WalkDir::new(fs_root).into_iter().filter_map(|e| e.ok()) {
+        let relative = entry
+          .path()
+          .strip_prefix(fs_root)
+          .expect("walkdir always yields paths nested under fs_root");
+        if relative.as_os_str().is_empty() {
+          continue;
+        }
+        let remote_path = format!("{}/{}", remote_dir, relative.to_string_lossy());
+
+        if entry.file_type().is_dir() {
+          let _ = sftp.mkdir(Path::new(&remote_path), 0o755);
+        } else if entry.file_type().is_file() {
+          let contents = fs_err::read(entry.path())
+            .context("Failed to read a PrivateFS file to replicate over SSH")?;
+          let mut remote_file = sftp
+            .create(Path::new(&remote_path))
+            .context("Failed to create a remote file over SFTP")?;
+          remote_file
+            .write_all(&contents)
+            .context("Failed to write a replicated file over SFTP")?;
+        }
+      }
+
+      Ok(())
+    }
+
+    fn run_remote(
+      &self,
+      session: &ssh2::Session,
+      remote_dir: &str,
+      spec: &TestSpec<'_>,
+    ) -> Result<TestOutcome, Box<dyn Error>> {
+      use std::io::Read;
+
+      let mut channel = session
+        .channel_session()
+        .context("Failed to open an SSH channel")?;
+
+      let remote_binary = format!("{}/{}", remote_dir, remote_binary_name(spec.test_binary));
+      let env_exports: String = spec
+        .env
+        .iter()
+        .map(|(k, v)| format!("export {}={}; ", shell_escape(k), shell_escape(v)))
+        .collect();
+      let command = format!(
+        "cd {dir} && {env}{binary} --exact {filter}",
+        dir = shell_escape(remote_dir),
+        env = env_exports,
+        binary = shell_escape(&remote_binary),
+        filter = shell_escape(spec.test_filter),
+      );
+
+      channel
+        .exec(&command)
+        .context("Failed to start the remote test process")?;
+
+      let mut stdout = Vec::new();
+      let mut stderr = Vec::new();
+      channel
+        .read_to_end(&mut stdout)
+        .context("Failed to read stdout from the remote test process")?;
+      channel
+        .stderr()
+        .read_to_end(&mut stderr)
+        .context("Failed to read stderr from the remote test process")?;
+      // A connection dropped mid-test surfaces here as an `Err`, which `run` turns into a
+      // harness-level failure rather than silently reporting a false pass.
+      channel
+        .wait_close()
+        .context("Lost the SSH connection before the remote test process exited")?;
+      let exit_status = channel
+        .exit_status()
+        .context("Failed to read the remote test process's exit status")?;
+
+      Ok(TestOutcome {
+        success: exit_status == 0,
+        stdout,
+        stderr,
+      })
+    }
+
+    fn cleanup(&self, session: &ssh2::Session, remote_dir: &str) {
+      // Best-effort: a cleanup failure shouldn't mask the test's actual result.
+      if let Ok(mut channel) = session.channel_session() {
+        if channel.exec(&format!("rm -rf {}", shell_escape(remote_dir))).is_ok() {
+          let _ = channel.wait_close();
+        }
+      }
+    }
+  }
+
+  #[cfg(feature = "ssh-runner")]
+  impl TestRunner for SshRunner {
+    fn run(&self, spec: &TestSpec<'_>) -> Result<TestOutcome, Box<dyn Error>> {
+      let session = self.connect()?;
+      let remote_dir = format!(
+        "{}/{}",
+        self.remote_tmp_prefix,
+        spec.test_filter.replace(['/', ':'], "_")
+      );
+
+      self
+        .replicate_fs(&session, &remote_dir, spec)
+        .map_err(|e| {
+          format!(
+            "Failed to replicate the test binary and PrivateFS contents to the remote host: {}",
+            e
+          )
+        })?;
+
+      let result = self.run_remote(&session, &remote_dir, spec);
+      self.cleanup(&session, &remote_dir);
+
+      result
+    }
+  }
+
+  #[cfg(feature = "ssh-runner")]
+  fn shell_escape(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+  }
+
+  /// Create `dir` and every ancestor that doesn't already exist, since `Sftp::mkdir` only
+  /// creates one level at a time and fails with `ENOENT` if its parent is missing.
+  #[cfg(feature = "ssh-runner")]
+  fn mkdir_recursive(sftp: &ssh2::Sftp, dir: &str) {
+    let mut ancestors: Vec<&Path> = Path::new(dir).ancestors().collect();
+    ancestors.reverse();
+    for ancestor in ancestors {
+      if ancestor.as_os_str().is_empty() || ancestor == Path::new("/") {
+        continue;
+      }
+      // Ignore failures here: "already exists" is the common case, and any other error
+      // (permissions, a full disk, ...) will surface anyway on the `sftp.create` calls that
+      // follow.
+      let _ = sftp.mkdir(ancestor, 0o755);
+    }
+  }
+
+  /// The name the test binary is uploaded under in `remote_dir`, shared between
+  /// `replicate_fs` (which uploads it) and `run_remote` (which execs it) so the two can't
+  /// drift apart.
+  #[cfg(feature = "ssh-runner")]
+  fn remote_binary_name(test_binary: &Path) -> String {
+    test_binary
+      .file_name()
+      .map(|n| n.to_string_lossy().into_owned())
+      .unwrap_or_else(|| "test-binary".to_owned())
+  }
 }
 
 // Async functionality